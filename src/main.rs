@@ -1,15 +1,28 @@
 //! A command-line tool for generating unzoomed level images from map tile images.
 //!
 //! This tool takes a folder containing map tile images at different zoom levels and generates
-//! unzoomed level images by combining the tiles from the last available zoom level.
+//! unzoomed level images by combining the tiles from the last available zoom level. Generated
+//! tiles are written to `--output` (or back into the source folder, for backward compatibility).
 
-use clap::Parser;
-use image::{imageops::resize, imageops::FilterType, io::Reader as ImageReader, ImageBuffer, Rgba, GenericImage};
-use indicatif::{ProgressBar, ProgressStyle};
+mod scheme;
+
+use clap::{Parser, ValueEnum};
+use image::{
+    codecs::jpeg::JpegEncoder, codecs::png::PngEncoder, codecs::webp::WebPEncoder,
+    imageops::resize, imageops::FilterType, io::Reader as ImageReader, ColorType, DynamicImage,
+    GenericImage, ImageBuffer, ImageEncoder, Rgba,
+};
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use scheme::Scheme;
 use std::{
-    fs::{create_dir_all, read_dir},
+    collections::{HashMap, HashSet},
+    fs::{create_dir_all, File},
+    io::{BufWriter, ErrorKind},
     path::Path,
     process,
+    sync::Once,
+    time::SystemTime,
 };
 
 /// Command-line arguments for the map tile unzooming tool.
@@ -26,6 +39,80 @@ struct Args {
     /// Least detailed zoom level.
     #[clap(long, default_value = "0")]
     min_zoom: u8,
+
+    /// Number of worker threads to use (defaults to the number of available cores).
+    #[clap(long)]
+    threads: Option<usize>,
+
+    /// File extension of the source tiles to read.
+    #[clap(long, default_value = "png")]
+    input_format: String,
+
+    /// Encoding used for generated tiles. WebP is always written lossless, regardless
+    /// of `--quality` (see `--quality`).
+    #[clap(long, value_enum, default_value = "png")]
+    output_format: OutputFormat,
+
+    /// Quality (1-100) used when the output format supports lossy compression. Has no
+    /// effect on WebP output, which this tool always writes lossless.
+    #[clap(long, default_value = "85")]
+    quality: u8,
+
+    /// Tile pyramid layout of the input (and output) folder.
+    #[clap(long, value_enum, default_value = "xyz")]
+    scheme: Scheme,
+
+    /// Regenerate every tile even if it is already newer than its inputs.
+    #[clap(long)]
+    force: bool,
+
+    /// Directory generated tiles are written to (defaults to `folder`, mutating it in place).
+    #[clap(long)]
+    output: Option<String>,
+
+    /// Width and height, in pixels, of a generated tile.
+    #[clap(long, default_value = "256")]
+    tile_size: u32,
+
+    /// Resampling kernel used both for the per-child resize and the final downsample.
+    #[clap(long, value_enum, default_value = "lanczos3")]
+    filter: FilterArg,
+}
+
+/// Resampling kernel exposed on the command line, mapped to `image::imageops::FilterType`.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FilterArg {
+    Nearest,
+    Triangle,
+    Lanczos3,
+}
+
+impl From<FilterArg> for FilterType {
+    fn from(filter: FilterArg) -> Self {
+        match filter {
+            FilterArg::Nearest => FilterType::Nearest,
+            FilterArg::Triangle => FilterType::Triangle,
+            FilterArg::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Encoding used when writing generated tiles.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+        }
+    }
 }
 
 fn main() {
@@ -36,26 +123,78 @@ fn main() {
         process::exit(1);
     }
 
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Failed to build thread pool");
+    }
+
+    let output_root = args.output.clone().unwrap_or_else(|| args.folder.clone());
+
     let mut zoom_level = find_last_zoom_level(&args.folder, args.max_zoom, args.min_zoom);
     println!("Starting zoom level: {}", zoom_level);
-    
+
+    // The first level of input tiles is read from the source folder in
+    // `args.input_format`; every level generated after that is read back from
+    // `output_root`, so from then on reads must follow `args.output_format` instead.
+    let mut input_root = args.folder.clone();
+    let mut read_format = args.input_format.clone();
+
     while zoom_level > args.min_zoom {
-        let image_paths = collect_image_paths(&args.folder, zoom_level);
-        println!("Total PNG files at zoom level {}: {}", zoom_level, image_paths.len());
+        let tiles = args.scheme.collect_tile_paths(&input_root, zoom_level, &read_format);
+        println!(
+            "Total {} files at zoom level {}: {}",
+            read_format.to_uppercase(),
+            zoom_level,
+            tiles.len()
+        );
+
+        // Indexed by tile coordinate so each output's freshness check can look up its
+        // (up to four) children here instead of re-statting them.
+        let modified_dates: HashMap<(u32, u32), SystemTime> = tiles
+            .iter()
+            .map(|tile| (args.scheme.parse_tile_path(&tile.path, &read_format), tile.modified_date))
+            .collect();
+
+        // Every 2x2 block of children shares one parent, so dedup down to the distinct
+        // parent coordinates before fanning out — otherwise up to four sibling tasks
+        // would race to write (and separately encode) the very same output tile.
+        let parents: Vec<(u32, u32)> = modified_dates
+            .keys()
+            .map(|&(x, y)| (x / 2, y / 2))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
 
         let output_zoom_level = zoom_level - 1;
-        let output_dir = format!("{}/{}", args.folder, output_zoom_level);
+        let output_dir = format!("{}/{}", output_root, output_zoom_level);
         create_dir_all(&output_dir).unwrap();
 
-        let progress_bar = create_progress_bar(image_paths.len() as u64);
+        let progress_bar = create_progress_bar(parents.len() as u64);
 
         println!("Generating {} level images...", output_zoom_level);
-        for image_path in image_paths {
-            process_image_path(&image_path, &args, output_zoom_level, zoom_level, &progress_bar);
-        }
+        parents
+            .par_iter()
+            .progress_with(progress_bar.clone())
+            .for_each(|&(back_x, back_y)| {
+                process_image_path(
+                    back_x,
+                    back_y,
+                    &args,
+                    &input_root,
+                    &read_format,
+                    &output_root,
+                    output_zoom_level,
+                    zoom_level,
+                    &modified_dates,
+                );
+            });
 
         progress_bar.finish_with_message("Done!");
 
+        input_root = output_root.clone();
+        read_format = args.output_format.extension().to_string();
         zoom_level -= 1;
     }
 }
@@ -67,33 +206,6 @@ fn find_last_zoom_level(folder: &str, max_zoom: u8, min_zoom: u8) -> u8 {
         .unwrap_or(min_zoom)
 }
 
-fn collect_image_paths(folder: &str, zoom_level: u8) -> Vec<String> {
-    let mut image_paths = Vec::new();
-    let zoom_dir = format!("{}/{}", folder, zoom_level);
-    if let Ok(x_entries) = read_dir(&zoom_dir) {
-        for x_entry in x_entries.filter_map(Result::ok) {
-            if let Ok(y_entries) = read_dir(x_entry.path()) {
-                for y_entry in y_entries.filter_map(Result::ok) {
-                    let path = y_entry.path();
-                    if let Some("png") = path.extension().and_then(|ext| ext.to_str()) {
-                        if let Some(image_path) = path.strip_prefix(folder).ok().and_then(|p| p.to_str()) {
-                            image_paths.push(image_path.to_string());
-                        }
-                    }
-                }
-            }
-        }
-    }
-    image_paths
-}
-
-fn parse_image_path(image_path: &str) -> (u32, u32) {
-    let parts: Vec<&str> = image_path.split('/').collect();
-    let x = parts[1].parse().unwrap();
-    let y = parts[2].parse().unwrap();
-    (x, y)
-}
-
 fn create_progress_bar(total: u64) -> ProgressBar {
     let progress_bar = ProgressBar::new(total);
     progress_bar.set_style(
@@ -113,34 +225,163 @@ fn fill_transparent(image: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, x: u32, y: u32,
     }
 }
 
+fn save_tile(image: &ImageBuffer<Rgba<u8>, Vec<u8>>, path: &str, format: OutputFormat, quality: u8) {
+    let writer = BufWriter::new(File::create(path).unwrap());
+    let (width, height) = image.dimensions();
+    match format {
+        OutputFormat::Png => PngEncoder::new(writer)
+            .write_image(image, width, height, ColorType::Rgba8)
+            .unwrap(),
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel, so drop it first; colours are kept as stored,
+            // not blended, so fully transparent filler comes out black.
+            let rgb_image = DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+            JpegEncoder::new_with_quality(writer, quality)
+                .write_image(&rgb_image, width, height, ColorType::Rgb8)
+                .unwrap();
+        }
+        OutputFormat::Webp => {
+            // image's WebP encoder only supports lossless output; `quality` is
+            // accepted for a uniform CLI surface but has no effect here.
+            static QUALITY_IGNORED_WARNING: Once = Once::new();
+            QUALITY_IGNORED_WARNING.call_once(|| {
+                eprintln!("warning: --output-format webp always encodes lossless; --quality is ignored");
+            });
+            let _ = quality;
+            WebPEncoder::new_lossless(writer)
+                .write_image(image, width, height, ColorType::Rgba8)
+                .unwrap();
+        }
+    }
+}
+
 fn process_image_path(
-    image_path: &str,
+    back_x: u32,
+    back_y: u32,
     args: &Args,
+    input_root: &str,
+    read_format: &str,
+    output_root: &str,
     output_zoom_level: u8,
     zoom_level: u8,
-    progress_bar: &ProgressBar,
+    modified_dates: &HashMap<(u32, u32), SystemTime>,
 ) {
-    if let Some((x, y)) = image_path.strip_suffix(".png").map(parse_image_path) {
-        let (back_x, back_y) = (x / 2, y / 2);
-        let back_path = format!("{}/{}/{}/{}.png", args.folder, output_zoom_level, back_x, back_y);
-
-        let mut output_image = ImageBuffer::new(512, 512);
-
-        for i in 0..2 {
-            for j in 0..2 {
-                let path = format!("{}/{}/{}/{}.png", args.folder, zoom_level, back_x * 2 + i, back_y * 2 + j);
-                if Path::new(&path).exists() {
-                    let image = ImageReader::open(path).unwrap().decode().unwrap();
-                    let resized_image = resize(&image, 256, 256, FilterType::Lanczos3);
-                    output_image.copy_from(&resized_image, i * 256, j * 256).unwrap();
-                } else {
-                    fill_transparent(&mut output_image, i * 256, j * 256, 256, 256);
-                }
+    let back_path = args.scheme.tile_path(
+        output_root,
+        output_zoom_level,
+        back_x,
+        back_y,
+        args.output_format.extension(),
+    );
+
+    let children: Vec<(u32, u32)> = (0..2)
+        .flat_map(|i| (0..2).map(move |j| (i, j)))
+        .map(|(i, j)| (back_x * 2 + i, args.scheme.child_y(back_y, j)))
+        .collect();
+
+    if !args.force && is_up_to_date(&back_path, &children, modified_dates) {
+        return;
+    }
+
+    let tile_size = args.tile_size;
+    let filter = FilterType::from(args.filter);
+    let mut mosaic = ImageBuffer::new(tile_size * 2, tile_size * 2);
+
+    for i in 0..2 {
+        for j in 0..2 {
+            let (child_x, child_y) = children[(i * 2 + j) as usize];
+            let path = args.scheme.tile_path(input_root, zoom_level, child_x, child_y, read_format);
+            if Path::new(&path).exists() {
+                let image = ImageReader::open(path).unwrap().decode().unwrap();
+                let resized_image = resize(&image, tile_size, tile_size, filter);
+                mosaic.copy_from(&resized_image, i * tile_size, j * tile_size).unwrap();
+            } else {
+                fill_transparent(&mut mosaic, i * tile_size, j * tile_size, tile_size, tile_size);
             }
         }
+    }
+
+    // The mosaic is a 2x2 grid of `tile_size`-sized children; downsample it by half so
+    // the output tile stays a standards-compliant `tile_size`x`tile_size` image.
+    let output_image = resize(&mosaic, tile_size, tile_size, filter);
+
+    // Multiple threads may race to create the same `{z}/{x}` directory; only a
+    // genuine failure (not "it's already there") should abort the run.
+    if let Err(err) = create_dir_all(Path::new(&back_path).parent().unwrap()) {
+        if err.kind() != ErrorKind::AlreadyExists {
+            panic!("Failed to create output directory for {}: {}", back_path, err);
+        }
+    }
+    save_tile(&output_image, &back_path, args.output_format, args.quality);
+}
+
+/// An output tile is up to date when it already exists and is newer than every child
+/// that is currently on disk. Children are looked up in the already-gathered
+/// `modified_dates` map rather than re-statting each one.
+fn is_up_to_date(back_path: &str, children: &[(u32, u32)], modified_dates: &HashMap<(u32, u32), SystemTime>) -> bool {
+    let Ok(output_modified) = Path::new(back_path).metadata().and_then(|metadata| metadata.modified()) else {
+        return false;
+    };
+    children
+        .iter()
+        .filter_map(|coords| modified_dates.get(coords))
+        .all(|&child_modified| child_modified < output_modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, thread::sleep, time::Duration};
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("mapback-rs-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn modified_of(path: &std::path::Path) -> SystemTime {
+        fs::metadata(path).unwrap().modified().unwrap()
+    }
+
+    #[test]
+    fn up_to_date_when_output_is_newer_than_every_child() {
+        let dir = scratch_dir("fresh");
+        let child_a = dir.join("a.png");
+        let child_b = dir.join("b.png");
+        fs::write(&child_a, b"a").unwrap();
+        fs::write(&child_b, b"b").unwrap();
+        sleep(Duration::from_millis(10));
+        let output = dir.join("out.png");
+        fs::write(&output, b"out").unwrap();
+
+        let modified_dates = HashMap::from([
+            ((0, 0), modified_of(&child_a)),
+            ((0, 1), modified_of(&child_b)),
+        ]);
+
+        assert!(is_up_to_date(output.to_str().unwrap(), &[(0, 0), (0, 1)], &modified_dates));
+    }
+
+    #[test]
+    fn stale_when_a_child_is_newer_than_the_output() {
+        let dir = scratch_dir("stale");
+        let output = dir.join("out.png");
+        fs::write(&output, b"out").unwrap();
+        sleep(Duration::from_millis(10));
+        let child = dir.join("a.png");
+        fs::write(&child, b"a").unwrap();
+
+        let modified_dates = HashMap::from([((0, 0), modified_of(&child))]);
+
+        assert!(!is_up_to_date(output.to_str().unwrap(), &[(0, 0)], &modified_dates));
+    }
+
+    #[test]
+    fn not_up_to_date_when_the_output_does_not_exist() {
+        let dir = scratch_dir("missing");
+        let missing_path = dir.join("missing.png");
 
-        create_dir_all(Path::new(&back_path).parent().unwrap()).unwrap();
-        output_image.save(&back_path).unwrap();
+        assert!(!is_up_to_date(missing_path.to_str().unwrap(), &[], &HashMap::new()));
     }
-    progress_bar.inc(1);
 }
@@ -0,0 +1,151 @@
+//! Tile-coordinate conventions for the pyramid layouts this tool can read and write.
+
+use clap::ValueEnum;
+use std::fs::{read_dir, DirEntry};
+use std::time::SystemTime;
+
+/// A tile on disk, together with the modified time needed for incremental regeneration.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Path relative to the pyramid root, e.g. `"3/1/2.png"`.
+    pub path: String,
+    pub modified_date: SystemTime,
+}
+
+/// Supported tile pyramid layouts.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// Google/OSM convention: `{z}/{x}/{y}.ext`, y counting from the top.
+    Xyz,
+    /// TMS convention: `{z}/{x}/{y}.ext`, y counting from the bottom.
+    Tms,
+    /// Deep Zoom convention: `{z}/{x}_{y}.ext`, flat per zoom level.
+    Dzi,
+}
+
+impl Scheme {
+    /// Lists the tiles (relative path + modified time) present at `zoom`.
+    pub fn collect_tile_paths(self, folder: &str, zoom: u8, extension: &str) -> Vec<FileEntry> {
+        let zoom_dir = format!("{}/{}", folder, zoom);
+        let mut entries = Vec::new();
+        match self {
+            Scheme::Xyz | Scheme::Tms => {
+                if let Ok(x_entries) = read_dir(&zoom_dir) {
+                    for x_entry in x_entries.filter_map(Result::ok) {
+                        if let Ok(y_entries) = read_dir(x_entry.path()) {
+                            for y_entry in y_entries.filter_map(Result::ok) {
+                                push_if_matching_extension(&mut entries, folder, &y_entry, extension);
+                            }
+                        }
+                    }
+                }
+            }
+            Scheme::Dzi => {
+                if let Ok(dir_entries) = read_dir(&zoom_dir) {
+                    for entry in dir_entries.filter_map(Result::ok) {
+                        push_if_matching_extension(&mut entries, folder, &entry, extension);
+                    }
+                }
+            }
+        }
+        entries
+    }
+
+    /// Parses the `(x, y)` tile coordinates out of a path relative to the pyramid root,
+    /// e.g. `"3/1/2.png"` for XYZ/TMS or `"3/1_2.png"` for DZI.
+    pub fn parse_tile_path(self, image_path: &str, extension: &str) -> (u32, u32) {
+        let stem = image_path
+            .strip_suffix(&format!(".{}", extension))
+            .unwrap_or(image_path);
+        match self {
+            Scheme::Xyz | Scheme::Tms => {
+                let parts: Vec<&str> = stem.split('/').collect();
+                (parts[1].parse().unwrap(), parts[2].parse().unwrap())
+            }
+            Scheme::Dzi => {
+                let file_name = stem.rsplit('/').next().unwrap();
+                let (x, y) = file_name.split_once('_').unwrap();
+                (x.parse().unwrap(), y.parse().unwrap())
+            }
+        }
+    }
+
+    /// Builds the path of the tile at `(zoom, x, y)` under `folder`, e.g. `"tiles/3/1/2.png"`.
+    pub fn tile_path(self, folder: &str, zoom: u8, x: u32, y: u32, extension: &str) -> String {
+        match self {
+            Scheme::Xyz | Scheme::Tms => format!("{}/{}/{}/{}.{}", folder, zoom, x, y, extension),
+            Scheme::Dzi => format!("{}/{}/{}_{}.{}", folder, zoom, x, y, extension),
+        }
+    }
+
+    /// Returns the tile-coordinate `y` of the child that should be composited into pixel
+    /// row `pixel_row` (0 = top, 1 = bottom) of a parent whose own tile y is `parent_y`.
+    ///
+    /// XYZ and DZI count y from the top at every level, so the child in pixel row `r` is
+    /// simply `2 * parent_y + r`. TMS counts from the bottom, so which child ends up on
+    /// top flips: the child in pixel row `r` is `2 * parent_y + (1 - r)`.
+    pub fn child_y(self, parent_y: u32, pixel_row: u32) -> u32 {
+        match self {
+            Scheme::Xyz | Scheme::Dzi => parent_y * 2 + pixel_row,
+            Scheme::Tms => parent_y * 2 + (1 - pixel_row),
+        }
+    }
+}
+
+fn push_if_matching_extension(entries: &mut Vec<FileEntry>, folder: &str, entry: &DirEntry, extension: &str) {
+    let path = entry.path();
+    if path.extension().and_then(|ext| ext.to_str()) != Some(extension) {
+        return;
+    }
+    let Some(relative_path) = path.strip_prefix(folder).ok().and_then(|p| p.to_str()) else {
+        return;
+    };
+    let Ok(modified_date) = entry.metadata().and_then(|metadata| metadata.modified()) else {
+        return;
+    };
+    entries.push(FileEntry {
+        path: relative_path.to_string(),
+        modified_date,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xyz_and_tms_parse_the_same_nested_path() {
+        assert_eq!(Scheme::Xyz.parse_tile_path("3/1/2.png", "png"), (1, 2));
+        assert_eq!(Scheme::Tms.parse_tile_path("3/1/2.png", "png"), (1, 2));
+    }
+
+    #[test]
+    fn dzi_parses_the_flat_x_y_filename() {
+        assert_eq!(Scheme::Dzi.parse_tile_path("3/1_2.png", "png"), (1, 2));
+    }
+
+    #[test]
+    fn xyz_and_tms_build_a_nested_path() {
+        assert_eq!(Scheme::Xyz.tile_path("tiles", 3, 1, 2, "png"), "tiles/3/1/2.png");
+        assert_eq!(Scheme::Tms.tile_path("tiles", 3, 1, 2, "png"), "tiles/3/1/2.png");
+    }
+
+    #[test]
+    fn dzi_builds_a_flat_x_y_path() {
+        assert_eq!(Scheme::Dzi.tile_path("tiles", 3, 1, 2, "png"), "tiles/3/1_2.png");
+    }
+
+    #[test]
+    fn xyz_and_dzi_child_y_does_not_flip_the_pixel_row() {
+        for scheme in [Scheme::Xyz, Scheme::Dzi] {
+            assert_eq!(scheme.child_y(5, 0), 10);
+            assert_eq!(scheme.child_y(5, 1), 11);
+        }
+    }
+
+    #[test]
+    fn tms_child_y_flips_the_pixel_row() {
+        assert_eq!(Scheme::Tms.child_y(5, 0), 11);
+        assert_eq!(Scheme::Tms.child_y(5, 1), 10);
+    }
+}